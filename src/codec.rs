@@ -0,0 +1,194 @@
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::stream::BodyFrame;
+use super::{Atom, Message, Priority, Queue, StreamFrame};
+
+// Every frame on the wire starts with a 1-byte tag identifying its kind,
+// then a 1-byte priority — mirrored from the sender's scheduling priority,
+// so a handler can reply to a request at the same priority it arrived at
+// instead of always falling back to a default — then a 4-byte big-endian
+// payload length, then the payload itself.
+const TAG_MESSAGE: u8 = 0;
+const TAG_STREAM: u8 = 1;
+const TAG_CANCEL: u8 = 2;
+
+
+pub(crate) struct Encoder<W> {
+    write: W,
+}
+
+impl<W: Write> Encoder<W> {
+    pub(crate) fn new(write: W) -> Self {
+        Encoder { write }
+    }
+
+    pub(crate) fn encode<P, NP, R>(
+        &mut self,
+        priority: Priority,
+        message: Message<P, NP, R>,
+    ) -> io::Result<()>
+    where
+        P: Atom,
+        NP: Atom,
+        R: Atom,
+        Message<P, NP, R>: Serialize,
+    {
+        let payload = serde_json::to_vec(&message)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.write_frame(TAG_MESSAGE, priority, &payload)
+    }
+
+    pub(crate) fn encode_stream_frame(
+        &mut self,
+        priority: Priority,
+        frame: StreamFrame,
+    ) -> io::Result<()> {
+        let mut payload = Vec::with_capacity(9);
+        payload.extend_from_slice(&frame.id.to_be_bytes());
+        payload.extend_from_slice(&frame.seq.to_be_bytes());
+        match frame.frame {
+            BodyFrame::Chunk(chunk) => {
+                payload.push(0);
+                payload.extend_from_slice(&chunk);
+            }
+            BodyFrame::End => payload.push(1),
+            BodyFrame::Error(message) => {
+                payload.push(2);
+                payload.extend_from_slice(message.as_bytes());
+            }
+        }
+        self.write_frame(TAG_STREAM, priority, &payload)
+    }
+
+    pub(crate) fn encode_cancel(&mut self, id: u32) -> io::Result<()> {
+        self.write_frame(TAG_CANCEL, Priority::MAX, &id.to_be_bytes())
+    }
+
+    fn write_frame(&mut self, tag: u8, priority: Priority, payload: &[u8]) -> io::Result<()> {
+        self.write.write_all(&[tag, priority])?;
+        self.write.write_all(&(payload.len() as u32).to_be_bytes())?;
+        self.write.write_all(payload)?;
+        self.write.flush()
+    }
+}
+
+pub(crate) struct Decoder<Rd, P, NP, R>
+where
+    P: Atom,
+    NP: Atom,
+    R: Atom,
+{
+    read: Rd,
+    queue: Arc<Mutex<Queue<P, NP, R>>>,
+}
+
+impl<Rd, P, NP, R> Decoder<Rd, P, NP, R>
+where
+    Rd: Read,
+    P: Atom,
+    NP: Atom,
+    R: Atom,
+{
+    pub(crate) fn new(read: Rd, queue: Arc<Mutex<Queue<P, NP, R>>>) -> Self {
+        Decoder { read, queue }
+    }
+
+    // Reads frames until it has one to hand back to the caller (a
+    // `Message`). Stream frames are routed straight to the body registry
+    // and cancel frames straight to the cancellation set, both kept on this
+    // same connection's `Queue`, so neither kind is ever visible above this
+    // layer.
+    pub(crate) fn decode(&mut self) -> io::Result<(Priority, Message<P, NP, R>)>
+    where
+        Message<P, NP, R>: DeserializeOwned,
+    {
+        loop {
+            let (tag, priority, payload) = self.read_frame()?;
+            match tag {
+                TAG_MESSAGE => {
+                    let message = serde_json::from_slice(&payload)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    return Ok((priority, message));
+                }
+                TAG_STREAM => {
+                    let frame = decode_stream_frame(&payload)?;
+                    let done = matches!(frame.frame, BodyFrame::End | BodyFrame::Error(_));
+
+                    // Only the lookup (and, once the body is finished, the
+                    // removal) happens under the `Queue` lock. The actual
+                    // send happens after it's released: the body's
+                    // `SyncSender` channel is bounded, so a reader stalled on a *different*
+                    // body must never be able to block this decoder thread
+                    // while it's holding the lock everything else on this
+                    // connection needs (`enqueue_request`, the cancel check
+                    // in `handle_message`, `shutdown_gracefully`'s drain).
+                    let sender = self.queue.lock().unwrap().take_body_sender(frame.id);
+                    if let Some(tx) = sender {
+                        let _ = tx.send(frame.frame);
+                    }
+                    if done {
+                        self.queue.lock().unwrap().forget_body(frame.id);
+                    }
+                }
+                TAG_CANCEL => {
+                    if payload.len() < 4 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "cancel frame shorter than its id",
+                        ));
+                    }
+                    let id = u32::from_be_bytes(payload[0..4].try_into().unwrap());
+                    self.queue.lock().unwrap().mark_cancelled(id);
+                }
+                other => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unknown frame tag {}", other),
+                    ))
+                }
+            }
+        }
+    }
+
+    fn read_frame(&mut self) -> io::Result<(u8, Priority, Vec<u8>)> {
+        let mut header = [0u8; 2];
+        self.read.read_exact(&mut header)?;
+        let tag = header[0];
+        let priority = header[1];
+
+        let mut len_buf = [0u8; 4];
+        self.read.read_exact(&mut len_buf)?;
+        let len = u32::from_be_bytes(len_buf) as usize;
+        let mut payload = vec![0u8; len];
+        self.read.read_exact(&mut payload)?;
+
+        Ok((tag, priority, payload))
+    }
+}
+
+fn decode_stream_frame(payload: &[u8]) -> io::Result<StreamFrame> {
+    if payload.len() < 9 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "stream frame shorter than its id/seq/kind header",
+        ));
+    }
+    let id = u32::from_be_bytes(payload[0..4].try_into().unwrap());
+    let seq = u32::from_be_bytes(payload[4..8].try_into().unwrap());
+    let frame = match payload[8] {
+        0 => BodyFrame::Chunk(payload[9..].to_vec()),
+        1 => BodyFrame::End,
+        2 => BodyFrame::Error(String::from_utf8_lossy(&payload[9..]).into_owned()),
+        other => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown stream frame kind {}", other),
+            ))
+        }
+    };
+    Ok(StreamFrame { id, seq, frame })
+}
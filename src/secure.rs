@@ -0,0 +1,361 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use ed25519_dalek::{Keypair, PublicKey};
+use sha2::{Digest, Sha512};
+use snow::{Builder, TransportState};
+
+use super::{default_timeout, spawn, Atom, Conn, Error, Handler, PeerId, Runtime};
+
+// Mutually-authenticated, Noise XX handshake: both sides carry a static
+// ed25519 keypair and prove ownership of it before any RPC traffic flows,
+// and both learn the other side's public key. `network_key` is a
+// pre-shared secret (distinct from the identity keys) so only peers
+// configured for the same network can complete a handshake at all.
+//
+// Noise's `25519` DH function is X25519, not ed25519, so the static keypair
+// can't be fed to it directly — each side converts its ed25519 keypair to
+// the corresponding X25519 keypair first (the standard birational map
+// between the Edwards and Montgomery forms of curve 25519, the same
+// conversion libsodium exposes as `crypto_sign_ed25519_*_to_curve25519`).
+// `PeerId` is therefore the peer's *converted* X25519 public key, not its
+// raw ed25519 public key; use `ed25519_public_to_peer_id` to convert a
+// known-good ed25519 public key into the form `peer_identity()` returns.
+const NOISE_PARAMS: &str = "Noise_XXpsk0_25519_ChaChaPoly_BLAKE2s";
+
+pub type NetworkKey = [u8; 32];
+pub type StaticKeypair = Keypair;
+
+// Noise frames are length-prefixed with a 16-bit length (the plaintext
+// codec framing inside them uses a 4-byte length), so a ciphertext can be
+// at most 65535 bytes. MAX_FRAME_LEN is the largest plaintext chunk we
+// feed Noise: with its 16-byte authentication tag added, the ciphertext
+// is exactly 65535 bytes.
+const MAX_FRAME_LEN: usize = 65519;
+
+struct Cipher {
+    transport: TransportState,
+}
+
+// Wraps any `Conn` with transparent encryption and authentication. Built
+// by completing a handshake first; afterwards `read`/`write` seal and open
+// frames without the caller (the decoder/encoder threads) needing to know
+// the connection is secured at all.
+pub struct SecureConn<C: Conn> {
+    inner: C,
+    cipher: Arc<Mutex<Cipher>>,
+    peer_identity: PeerId,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+}
+
+impl<C: Conn> Read for SecureConn<C> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.read_pos < self.read_buf.len() {
+                let n = std::cmp::min(out.len(), self.read_buf.len() - self.read_pos);
+                out[..n].copy_from_slice(&self.read_buf[self.read_pos..self.read_pos + n]);
+                self.read_pos += n;
+                return Ok(n);
+            }
+
+            let ciphertext = read_frame(&mut self.inner)?;
+            let mut plaintext = vec![0u8; ciphertext.len()];
+            let n = {
+                let mut cipher = self.cipher.lock().unwrap();
+                cipher
+                    .transport
+                    .read_message(&ciphertext, &mut plaintext)
+                    .map_err(noise_err)?
+            };
+            plaintext.truncate(n);
+            self.read_buf = plaintext;
+            self.read_pos = 0;
+        }
+    }
+}
+
+impl<C: Conn> Write for SecureConn<C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let chunk_len = std::cmp::min(buf.len(), MAX_FRAME_LEN);
+        let chunk = &buf[..chunk_len];
+        let mut ciphertext = vec![0u8; chunk_len + 16];
+        let n = {
+            let mut cipher = self.cipher.lock().unwrap();
+            cipher
+                .transport
+                .write_message(chunk, &mut ciphertext)
+                .map_err(noise_err)?
+        };
+        ciphertext.truncate(n);
+        write_frame(&mut self.inner, &ciphertext)?;
+        Ok(chunk_len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<C: Conn> Conn for SecureConn<C> {
+    fn try_clone(&self) -> io::Result<Self> {
+        // The inner connection is duplicated like any other `Conn`, but the
+        // cipher state is shared: Noise keeps independent send/receive
+        // nonce counters in one `TransportState`, so the read half and the
+        // write half can each drive it from their own thread through the
+        // same `Mutex` without stepping on each other.
+        Ok(SecureConn {
+            inner: self.inner.try_clone()?,
+            cipher: self.cipher.clone(),
+            peer_identity: self.peer_identity,
+            read_buf: Vec::new(),
+            read_pos: 0,
+        })
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    fn peer_identity(&self) -> Option<PeerId> {
+        Some(self.peer_identity)
+    }
+}
+
+fn noise_err(e: snow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("noise error: {:?}", e))
+}
+
+fn write_frame<W: Write>(w: &mut W, data: &[u8]) -> io::Result<()> {
+    w.write_all(&(data.len() as u16).to_be_bytes())?;
+    w.write_all(data)
+}
+
+fn read_frame<Rd: Read>(r: &mut Rd) -> io::Result<Vec<u8>> {
+    let mut len_buf = [0u8; 2];
+    r.read_exact(&mut len_buf)?;
+    let len = u16::from_be_bytes(len_buf) as usize;
+    let mut buf = vec![0u8; len];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+// Converts an ed25519 signing key to the X25519 private scalar Noise
+// actually uses: hash the seed with SHA-512 and clamp the low half, exactly
+// as RFC 8032 derives the ed25519 scalar from the same seed.
+fn ed25519_to_x25519_private(static_key: &StaticKeypair) -> [u8; 32] {
+    let hash = Sha512::digest(static_key.secret.as_bytes());
+    let mut scalar = [0u8; 32];
+    scalar.copy_from_slice(&hash[..32]);
+    scalar[0] &= 248;
+    scalar[31] &= 127;
+    scalar[31] |= 64;
+    scalar
+}
+
+// Converts an ed25519 public key (a point on the twisted Edwards curve) to
+// the corresponding X25519 public key (its u-coordinate on the birationally
+// equivalent Montgomery curve).
+fn ed25519_to_x25519_public(public: &PublicKey) -> Result<[u8; 32], Error> {
+    CompressedEdwardsY::from_slice(public.as_bytes())
+        .decompress()
+        .map(|point| point.to_montgomery().to_bytes())
+        .ok_or_else(|| Error::TransportError("invalid ed25519 public key".into()))
+}
+
+// Converts a peer's ed25519 public key into the form `peer_identity()`
+// returns for that peer, so it can be checked against an allow-list.
+pub fn ed25519_public_to_peer_id(public: &PublicKey) -> Result<PeerId, Error> {
+    ed25519_to_x25519_public(public)
+}
+
+fn builder(static_key: &StaticKeypair, network_key: &NetworkKey) -> Result<Builder<'static>, Error> {
+    Builder::new(NOISE_PARAMS.parse().unwrap())
+        .local_private_key(&ed25519_to_x25519_private(static_key))
+        .psk(0, network_key)
+        .map_err(|e| Error::TransportError(format!("noise setup failed: {:?}", e)))
+}
+
+fn peer_identity_of(noise: &snow::HandshakeState) -> Result<PeerId, Error> {
+    let remote_static = noise
+        .get_remote_static()
+        .ok_or_else(|| Error::TransportError("peer did not present a static key".into()))?;
+    let mut id = [0u8; 32];
+    id.copy_from_slice(remote_static);
+    Ok(id)
+}
+
+fn handshake_initiator<C: Conn>(
+    mut conn: C,
+    static_key: &StaticKeypair,
+    network_key: &NetworkKey,
+) -> Result<SecureConn<C>, Error> {
+    let mut noise = builder(static_key, network_key)?
+        .build_initiator()
+        .map_err(|e| Error::TransportError(format!("noise init failed: {:?}", e)))?;
+
+    let mut buf = vec![0u8; MAX_FRAME_LEN];
+
+    let n = noise
+        .write_message(&[], &mut buf)
+        .map_err(|e| Error::TransportError(format!("{:?}", e)))?;
+    write_frame(&mut conn, &buf[..n])?;
+
+    let msg2 = read_frame(&mut conn)?;
+    noise
+        .read_message(&msg2, &mut buf)
+        .map_err(|e| Error::TransportError(format!("{:?}", e)))?;
+
+    let n = noise
+        .write_message(&[], &mut buf)
+        .map_err(|e| Error::TransportError(format!("{:?}", e)))?;
+    write_frame(&mut conn, &buf[..n])?;
+
+    let peer_identity = peer_identity_of(&noise)?;
+    let transport = noise
+        .into_transport_mode()
+        .map_err(|e| Error::TransportError(format!("{:?}", e)))?;
+
+    Ok(SecureConn {
+        inner: conn,
+        cipher: Arc::new(Mutex::new(Cipher { transport })),
+        peer_identity,
+        read_buf: Vec::new(),
+        read_pos: 0,
+    })
+}
+
+fn handshake_responder<C: Conn>(
+    mut conn: C,
+    static_key: &StaticKeypair,
+    network_key: &NetworkKey,
+) -> Result<SecureConn<C>, Error> {
+    let mut noise = builder(static_key, network_key)?
+        .build_responder()
+        .map_err(|e| Error::TransportError(format!("noise init failed: {:?}", e)))?;
+
+    let mut buf = vec![0u8; MAX_FRAME_LEN];
+
+    let msg1 = read_frame(&mut conn)?;
+    noise
+        .read_message(&msg1, &mut buf)
+        .map_err(|e| Error::TransportError(format!("{:?}", e)))?;
+
+    let n = noise
+        .write_message(&[], &mut buf)
+        .map_err(|e| Error::TransportError(format!("{:?}", e)))?;
+    write_frame(&mut conn, &buf[..n])?;
+
+    let msg3 = read_frame(&mut conn)?;
+    noise
+        .read_message(&msg3, &mut buf)
+        .map_err(|e| Error::TransportError(format!("{:?}", e)))?;
+
+    let peer_identity = peer_identity_of(&noise)?;
+    let transport = noise
+        .into_transport_mode()
+        .map_err(|e| Error::TransportError(format!("{:?}", e)))?;
+
+    Ok(SecureConn {
+        inner: conn,
+        cipher: Arc::new(Mutex::new(Cipher { transport })),
+        peer_identity,
+        read_buf: Vec::new(),
+        read_pos: 0,
+    })
+}
+
+// Connect to a TCP address, perform the client (initiator) side of the
+// handshake, then spawn a new RPC system over the resulting secure
+// connection. Mirrors `connect_tcp`.
+pub fn connect_tcp_secure<AH, H, P, NP, R>(
+    handler: AH,
+    addr: &SocketAddr,
+    static_key: &StaticKeypair,
+    network_key: &NetworkKey,
+) -> Result<Runtime<SecureConn<TcpStream>, P, NP, R>, Error>
+where
+    AH: Into<Arc<H>>,
+    H: Handler<P, NP, R> + 'static,
+    P: Atom,
+    NP: Atom,
+    R: Atom,
+{
+    let conn = TcpStream::connect_timeout(addr, default_timeout())?;
+    let conn = handshake_initiator(conn, static_key, network_key)?;
+    spawn(handler, conn)
+}
+
+// Accept the server (responder) side of the handshake over an already
+// connected transport (e.g. from a `TcpListener`), then spawn a new RPC
+// system over the resulting secure connection.
+pub fn spawn_secure<C, AH, H, P, NP, R>(
+    handler: AH,
+    conn: C,
+    static_key: &StaticKeypair,
+    network_key: &NetworkKey,
+) -> Result<Runtime<SecureConn<C>, P, NP, R>, Error>
+where
+    C: Conn,
+    AH: Into<Arc<H>>,
+    H: Handler<P, NP, R> + 'static,
+    P: Atom,
+    NP: Atom,
+    R: Atom,
+{
+    let conn = handshake_responder(conn, static_key, network_key)?;
+    spawn(handler, conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+    use std::net::TcpListener;
+
+    #[test]
+    fn ed25519_to_x25519_private_is_a_valid_clamped_scalar() {
+        let keypair = Keypair::generate(&mut OsRng);
+        let scalar = ed25519_to_x25519_private(&keypair);
+        assert_eq!(scalar[0] & 0b0000_0111, 0);
+        assert_eq!(scalar[31] & 0b1000_0000, 0);
+        assert_eq!(scalar[31] & 0b0100_0000, 0b0100_0000);
+    }
+
+    // The handshake negotiates each side's *converted* X25519 static key,
+    // so the peer identity a connection reports must match converting the
+    // other side's ed25519 public key through the same function — not the
+    // raw ed25519 public key bytes.
+    #[test]
+    fn handshake_peer_identity_matches_converted_ed25519_public_key() {
+        let network_key: NetworkKey = [7u8; 32];
+        let client_key = Keypair::generate(&mut OsRng);
+        let server_key = Keypair::generate(&mut OsRng);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server_network_key = network_key;
+        let server_key_clone_public = server_key.public;
+        let client_key_clone_public = client_key.public;
+        let server_handle = std::thread::spawn(move || {
+            let (conn, _) = listener.accept().unwrap();
+            let secure = handshake_responder(conn, &server_key, &server_network_key).unwrap();
+            assert_eq!(
+                secure.peer_identity,
+                ed25519_public_to_peer_id(&client_key_clone_public).unwrap()
+            );
+        });
+
+        let conn = TcpStream::connect(addr).unwrap();
+        let secure = handshake_initiator(conn, &client_key, &network_key).unwrap();
+        assert_eq!(
+            secure.peer_identity,
+            ed25519_public_to_peer_id(&server_key_clone_public).unwrap()
+        );
+
+        server_handle.join().unwrap();
+    }
+}
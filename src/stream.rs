@@ -0,0 +1,304 @@
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::sync::{mpsc, Arc};
+
+use super::{Atom, Priority, Sink, SinkValue, PRIORITY_NORMAL};
+
+// Chunks are kept as plain byte buffers rather than pulling in a dedicated
+// bytes crate, consistent with the rest of this crate's minimal dependency
+// footprint.
+pub type Chunk = Vec<u8>;
+
+// Bounding the channel means a slow reader on the receiving side applies
+// backpressure to the producer instead of letting chunks pile up in memory.
+const BODY_CHANNEL_CAPACITY: usize = 16;
+
+pub enum BodyFrame {
+    Chunk(Chunk),
+    End,
+    Error(String),
+}
+
+// One frame of a body as it travels over the wire: tagged with the id of
+// the request/response it belongs to and its sequence number within that
+// body, so it can be interleaved with unrelated traffic. The decoder does
+// not reorder by `seq`: a body's frames reach its `Body` in the order they
+// arrive, which is the order one `BodySender` pushed them.
+pub struct StreamFrame {
+    pub id: u32,
+    pub seq: u32,
+    pub frame: BodyFrame,
+}
+
+// The producing half of a body. Chunks pushed here are handed to the
+// connection's priority sink like any other outgoing message, so a body
+// never bypasses the scheduler that orders the rest of the traffic.
+pub struct BodySender<P, NP, R>
+where
+    P: Atom,
+    NP: Atom,
+    R: Atom,
+{
+    id: u32,
+    next_seq: u32,
+    priority: Priority,
+    sink: Arc<Sink<P, NP, R>>,
+}
+
+impl<P, NP, R> BodySender<P, NP, R>
+where
+    P: Atom,
+    NP: Atom,
+    R: Atom,
+{
+    pub(crate) fn new(id: u32, sink: Arc<Sink<P, NP, R>>) -> Self {
+        BodySender {
+            id,
+            next_seq: 0,
+            priority: PRIORITY_NORMAL,
+            sink,
+        }
+    }
+
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn send_chunk(&mut self, chunk: Chunk) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.sink.push(
+            self.priority,
+            SinkValue::Stream(StreamFrame {
+                id: self.id,
+                seq,
+                frame: BodyFrame::Chunk(chunk),
+            }),
+        );
+    }
+
+    pub fn finish(&self) {
+        self.sink.push(
+            self.priority,
+            SinkValue::Stream(StreamFrame {
+                id: self.id,
+                seq: self.next_seq,
+                frame: BodyFrame::End,
+            }),
+        );
+    }
+
+    pub fn fail(&self, message: String) {
+        self.sink.push(
+            self.priority,
+            SinkValue::Stream(StreamFrame {
+                id: self.id,
+                seq: self.next_seq,
+                frame: BodyFrame::Error(message),
+            }),
+        );
+    }
+}
+
+// The consuming half of a body: a `Read` handle that blocks for the next
+// chunk as the caller drains the one already buffered.
+pub struct Body {
+    rx: mpsc::Receiver<BodyFrame>,
+    buf: Chunk,
+    pos: usize,
+    done: bool,
+}
+
+impl Read for Body {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.pos < self.buf.len() {
+                let n = std::cmp::min(out.len(), self.buf.len() - self.pos);
+                out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+                self.pos += n;
+                return Ok(n);
+            }
+            if self.done {
+                return Ok(0);
+            }
+            match self.rx.recv() {
+                Ok(BodyFrame::Chunk(chunk)) => {
+                    self.buf = chunk;
+                    self.pos = 0;
+                }
+                Ok(BodyFrame::End) | Err(_) => {
+                    self.done = true;
+                    return Ok(0);
+                }
+                Ok(BodyFrame::Error(message)) => {
+                    self.done = true;
+                    return Err(io::Error::new(io::ErrorKind::Other, message));
+                }
+            }
+        }
+    }
+}
+
+// Registry of bodies currently being received, keyed by the id of the
+// request/response they belong to. The decoder looks up the matching
+// sender as frames arrive and feeds them in; the `Body` handle was already
+// handed to whoever called `register`.
+#[derive(Default)]
+pub(crate) struct BodyRegistry {
+    senders: HashMap<u32, mpsc::SyncSender<BodyFrame>>,
+}
+
+impl BodyRegistry {
+    pub(crate) fn new() -> Self {
+        BodyRegistry {
+            senders: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn register(&mut self, id: u32) -> Body {
+        let (tx, rx) = mpsc::sync_channel(BODY_CHANNEL_CAPACITY);
+        self.senders.insert(id, tx);
+        Body {
+            rx,
+            buf: Vec::new(),
+            pos: 0,
+            done: false,
+        }
+    }
+
+    // Returns a clone of the sender registered for `id`, if any, so the
+    // caller can feed it a frame without holding whatever lock guards this
+    // registry (the `send` on a full channel blocks, and this registry
+    // normally lives behind the same `Queue` mutex as everything else on
+    // the connection).
+    pub(crate) fn take_sender(&mut self, id: u32) -> Option<mpsc::SyncSender<BodyFrame>> {
+        self.senders.get(&id).cloned()
+    }
+
+    // Drops the registered sender for `id` once its body is finished, so a
+    // sender never lingers past the last frame its body will ever see.
+    pub(crate) fn forget(&mut self, id: u32) {
+        self.senders.remove(&id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read as _;
+
+    // Mirrors how `codec::Decoder::decode` drives the registry: look up (and
+    // on the last frame, forget) the sender, then feed it outside of
+    // whatever lock guards the registry itself.
+    fn dispatch(registry: &mut BodyRegistry, frame: StreamFrame) {
+        let done = matches!(frame.frame, BodyFrame::End | BodyFrame::Error(_));
+        if let Some(tx) = registry.take_sender(frame.id) {
+            let _ = tx.send(frame.frame);
+        }
+        if done {
+            registry.forget(frame.id);
+        }
+    }
+
+    #[test]
+    fn reassembles_chunks_in_order_and_reads_zero_at_end() {
+        let mut registry = BodyRegistry::new();
+        let mut body = registry.register(1);
+
+        dispatch(
+            &mut registry,
+            StreamFrame {
+                id: 1,
+                seq: 0,
+                frame: BodyFrame::Chunk(vec![1, 2, 3]),
+            },
+        );
+        dispatch(
+            &mut registry,
+            StreamFrame {
+                id: 1,
+                seq: 1,
+                frame: BodyFrame::Chunk(vec![4, 5]),
+            },
+        );
+        dispatch(
+            &mut registry,
+            StreamFrame {
+                id: 1,
+                seq: 2,
+                frame: BodyFrame::End,
+            },
+        );
+
+        let mut out = Vec::new();
+        body.read_to_end(&mut out).unwrap();
+        assert_eq!(out, vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn surfaces_a_remote_error_as_an_io_error() {
+        let mut registry = BodyRegistry::new();
+        let mut body = registry.register(2);
+
+        dispatch(
+            &mut registry,
+            StreamFrame {
+                id: 2,
+                seq: 0,
+                frame: BodyFrame::Error("upstream exploded".into()),
+            },
+        );
+
+        let mut out = Vec::new();
+        let err = body.read_to_end(&mut out).unwrap_err();
+        assert_eq!(err.to_string(), "upstream exploded");
+    }
+
+    #[test]
+    fn dispatch_to_an_unregistered_id_is_a_silent_no_op() {
+        let mut registry = BodyRegistry::new();
+        // No `register` call for id 3; this must not panic.
+        dispatch(
+            &mut registry,
+            StreamFrame {
+                id: 3,
+                seq: 0,
+                frame: BodyFrame::Chunk(vec![9]),
+            },
+        );
+    }
+
+    #[test]
+    fn a_slow_reader_blocks_the_producer_once_the_channel_is_full() {
+        let mut registry = BodyRegistry::new();
+        let mut body = registry.register(4);
+
+        // Fill the bounded channel without anyone draining it.
+        for i in 0..BODY_CHANNEL_CAPACITY {
+            dispatch(
+                &mut registry,
+                StreamFrame {
+                    id: 4,
+                    seq: i as u32,
+                    frame: BodyFrame::Chunk(vec![i as u8]),
+                },
+            );
+        }
+
+        // The bounded channel holds exactly `BODY_CHANNEL_CAPACITY` frames,
+        // so filling it to that point doesn't block this thread. One more
+        // frame no longer fits: a blocking `send` would wait here until the
+        // reader drains a chunk.
+        let tx = registry.take_sender(4).unwrap();
+        assert!(matches!(
+            tx.try_send(BodyFrame::Chunk(vec![0xff])),
+            Err(std::sync::mpsc::TrySendError::Full(_))
+        ));
+
+        // The first chunk sent is still the first one `read` returns.
+        let mut byte = [0u8; 1];
+        body.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], 0);
+    }
+}
@@ -1,21 +1,38 @@
 use super::{Atom, Error, Message, PendingRequests};
 
 use std::any::Any;
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::io::{self, Read, Write};
 use std::net::{Shutdown, SocketAddr, TcpStream};
-use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
 use std::thread::JoinHandle;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use log::*;
 
 mod codec;
 use codec::{Decoder, Encoder};
 
+mod stream;
+pub use stream::{Body, BodySender, Chunk};
+use stream::{BodyFrame, BodyRegistry, StreamFrame};
+
+mod secure;
+pub use secure::{connect_tcp_secure, spawn_secure, NetworkKey, SecureConn, StaticKeypair};
+
+// The verified public key of a handshake-authenticated peer, as surfaced
+// by `SecureConn`. Plain, unauthenticated transports have none.
+pub type PeerId = [u8; 32];
+
 pub trait Conn: Read + Write + Send + Sized + 'static {
     fn try_clone(&self) -> io::Result<Self>;
     fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+
+    fn peer_identity(&self) -> Option<PeerId> {
+        None
+    }
 }
 
 impl Conn for std::net::TcpStream {
@@ -35,6 +52,10 @@ where
     R: Atom,
 {
     fn handle(&self, client: Client<P, NP, R>, params: P) -> Result<R, Error>;
+
+    // Called when a one-way notification is received. Notifications
+    // have no response, so the default implementation simply ignores them.
+    fn on_notification(&self, _client: Client<P, NP, R>, _params: NP) {}
 }
 
 impl<P, NP, R, F> Handler<P, NP, R> for F
@@ -49,6 +70,19 @@ where
     }
 }
 
+pub type Priority = u8;
+
+pub const PRIORITY_NORMAL: Priority = 128;
+pub const PRIORITY_HIGH: Priority = 255;
+
+// The lowest priority there is, so a graceful shutdown's final `Shutdown`
+// entry never overtakes traffic that was already queued ahead of it: that
+// traffic has an equal or higher priority, and equal priorities pop in
+// FIFO order. This matters because the encoder stops as soon as it pops `Shutdown`, so
+// anything still behind it in the heap would otherwise be silently
+// dropped.
+const PRIORITY_SHUTDOWN: Priority = 0;
+
 pub enum SinkValue<P, NP, R>
 where
     P: Atom,
@@ -57,6 +91,143 @@ where
 {
     Shutdown,
     Message(Message<P, NP, R>),
+    Stream(StreamFrame),
+    // A request we've given up on; tells the remote it can drop the
+    // handler thread computing the now-orphaned response, if it hasn't
+    // already sent it.
+    Cancel(u32),
+}
+
+// An entry waiting to be written out. Ordered first by `priority` (higher
+// goes first), then by `sequence` (lower, i.e. older, goes first) so that
+// messages of equal priority are still flushed in FIFO order.
+struct SinkEntry<P, NP, R>
+where
+    P: Atom,
+    NP: Atom,
+    R: Atom,
+{
+    priority: Priority,
+    sequence: u64,
+    value: SinkValue<P, NP, R>,
+}
+
+impl<P, NP, R> PartialEq for SinkEntry<P, NP, R>
+where
+    P: Atom,
+    NP: Atom,
+    R: Atom,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.priority == other.priority && self.sequence == other.sequence
+    }
+}
+
+impl<P, NP, R> Eq for SinkEntry<P, NP, R>
+where
+    P: Atom,
+    NP: Atom,
+    R: Atom,
+{
+}
+
+impl<P, NP, R> PartialOrd for SinkEntry<P, NP, R>
+where
+    P: Atom,
+    NP: Atom,
+    R: Atom,
+{
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<P, NP, R> Ord for SinkEntry<P, NP, R>
+where
+    P: Atom,
+    NP: Atom,
+    R: Atom,
+{
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+// Bounds how many entries the sink will hold before `push` blocks. Without
+// a cap, a body streaming chunks faster than the encoder (or the remote
+// reader on the other end) can drain them would grow the heap without
+// bound; with it, a fast producer backs up into this bounded buffer and
+// then blocks, so the backpressure a slow remote reader applies to a body
+// actually reaches the thread calling `BodySender::send_chunk`.
+const SINK_CAPACITY: usize = 256;
+
+// Shared sink feeding the encoder thread: a priority heap instead of a plain
+// FIFO channel, so a burst of bulk traffic can't head-of-line-block an
+// urgent control message.
+pub(crate) struct Sink<P, NP, R>
+where
+    P: Atom,
+    NP: Atom,
+    R: Atom,
+{
+    heap: Mutex<BinaryHeap<SinkEntry<P, NP, R>>>,
+    ready: Condvar,
+    space: Condvar,
+    next_sequence: AtomicU64,
+}
+
+impl<P, NP, R> Sink<P, NP, R>
+where
+    P: Atom,
+    NP: Atom,
+    R: Atom,
+{
+    fn new() -> Self {
+        Sink {
+            heap: Mutex::new(BinaryHeap::new()),
+            ready: Condvar::new(),
+            space: Condvar::new(),
+            next_sequence: AtomicU64::new(0),
+        }
+    }
+
+    // Blocks while the heap is already at `SINK_CAPACITY`, so a producer
+    // outpacing the encoder is slowed down rather than left to grow the
+    // heap without bound.
+    pub(crate) fn push(&self, priority: Priority, value: SinkValue<P, NP, R>) {
+        let sequence = self.next_sequence.fetch_add(1, AtomicOrdering::SeqCst);
+        let mut heap = self.heap.lock().unwrap();
+        while heap.len() >= SINK_CAPACITY {
+            heap = self.space.wait(heap).unwrap();
+        }
+        heap.push(SinkEntry {
+            priority,
+            sequence,
+            value,
+        });
+        self.ready.notify_one();
+    }
+
+    // Blocks until the highest-priority pending message is available.
+    // Returns the priority it was pushed with alongside the value, so a
+    // response can be sent back out at the same priority as the request
+    // that prompted it.
+    fn pop(&self) -> (Priority, SinkValue<P, NP, R>) {
+        let mut heap = self.heap.lock().unwrap();
+        loop {
+            if let Some(entry) = heap.pop() {
+                self.space.notify_one();
+                return (entry.priority, entry.value);
+            }
+            heap = self.ready.wait(heap).unwrap();
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.heap.lock().unwrap().is_empty()
+    }
 }
 
 pub struct Client<P, NP, R>
@@ -66,7 +237,8 @@ where
     R: Atom,
 {
     queue: Arc<Mutex<Queue<P, NP, R>>>,
-    sink: mpsc::Sender<SinkValue<P, NP, R>>,
+    sink: Arc<Sink<P, NP, R>>,
+    peer_identity: Option<PeerId>,
 }
 
 impl<P, NP, R> Client<P, NP, R>
@@ -79,30 +251,152 @@ where
         Client {
             queue: self.queue.clone(),
             sink: self.sink.clone(),
+            peer_identity: self.peer_identity,
         }
     }
 
+    // The verified public key of the remote peer, if this connection went
+    // through an authenticated handshake (see `SecureConn`). `None` over a
+    // plain, unauthenticated transport.
+    pub fn peer_identity(&self) -> Option<PeerId> {
+        self.peer_identity
+    }
+
     pub fn call_raw(&self, params: P) -> Result<Message<P, NP, R>, Error> {
+        self.call_raw_with_priority(params, PRIORITY_NORMAL)
+    }
+
+    pub fn call_raw_with_priority(
+        &self,
+        params: P,
+        priority: Priority,
+    ) -> Result<Message<P, NP, R>, Error> {
+        let (_id, rx) = self.enqueue_request(params, priority)?;
+        rx.recv()?
+    }
+
+    // Like `call_raw`, but gives up after `timeout` instead of blocking on
+    // an unresponsive or dropped peer forever. On expiry the in-flight
+    // entry is removed and a cancellation is sent for `id` so the remote
+    // can drop the now-pointless work instead of computing a response
+    // nobody is waiting for.
+    pub fn call_raw_timeout(
+        &self,
+        params: P,
+        timeout: Duration,
+    ) -> Result<Message<P, NP, R>, Error> {
+        self.call_raw_timeout_with_priority(params, timeout, PRIORITY_NORMAL)
+    }
+
+    pub fn call_raw_timeout_with_priority(
+        &self,
+        params: P,
+        timeout: Duration,
+        priority: Priority,
+    ) -> Result<Message<P, NP, R>, Error> {
+        let (id, rx) = self.enqueue_request(params, priority)?;
+
+        match rx.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                {
+                    let mut queue = self.queue.lock()?;
+                    queue.in_flight_requests.remove(&id);
+                }
+                self.sink.push(PRIORITY_HIGH, SinkValue::Cancel(id));
+                Err(Error::Timeout)
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(Error::TransportError(
+                "connection closed before a response arrived".into(),
+            )),
+        }
+    }
+
+    fn enqueue_request(
+        &self,
+        params: P,
+        priority: Priority,
+    ) -> Result<(u32, mpsc::Receiver<Result<Message<P, NP, R>, Error>>), Error> {
+        let method = params.method();
+        let (tx, rx) = mpsc::channel::<Result<Message<P, NP, R>, Error>>();
+
+        // The closing check, id allocation, and in-flight registration all
+        // happen under one lock acquisition. Splitting them (as a prior
+        // version did) left a window where `shutdown_gracefully` could
+        // observe `in_flight_requests` empty, drain it, and send `Shutdown`
+        // in between this call's closing-check and its insert — leaving
+        // this caller's `rx.recv()` with nobody left to ever answer it.
         let id = {
             let mut queue = self.queue.lock()?;
-            queue.next_id()
+            if queue.closing {
+                return Err(Error::TransportError(
+                    "connection is shutting down".into(),
+                ));
+            }
+            let id = queue.next_id();
+            queue
+                .in_flight_requests
+                .insert(id, InFlightRequest { method, tx });
+            id
         };
 
-        let method = params.method();
         let m = Message::Request { id, params };
+        self.sink.push(priority, SinkValue::Message(m));
+        Ok((id, rx))
+    }
 
-        let (tx, rx) = mpsc::channel::<Message<P, NP, R>>();
-        let in_flight = InFlightRequest { method, tx };
-        {
-            let mut queue = self.queue.lock()?;
-            queue.in_flight_requests.insert(id, in_flight);
+    // `shutdown_gracefully` stops draining the sink once the encoder
+    // thread has seen `Shutdown`, so anything pushed after that point
+    // would sit in the heap forever with nobody left to pop it. Only
+    // `notify_with_priority` calls this; `enqueue_request` and `open_body`
+    // make the same check inline. `receive_body`, chunks pushed through an
+    // already-open `BodySender`, and `Cancel` entries are not checked.
+    fn check_not_closing(&self) -> Result<(), Error> {
+        if self.queue.lock()?.closing {
+            return Err(Error::TransportError(
+                "connection is shutting down".into(),
+            ));
         }
+        Ok(())
+    }
 
-        {
-            let sink = self.sink.clone();
-            sink.send(SinkValue::Message(m))?;
-        }
-        Ok(rx.recv()?)
+    // Send a fire-and-forget notification. Unlike `call_raw`, this does not
+    // allocate an id, register an in-flight request, or wait for a response.
+    pub fn notify(&self, params: NP) -> Result<(), Error> {
+        self.notify_with_priority(params, PRIORITY_NORMAL)
+    }
+
+    pub fn notify_with_priority(&self, params: NP, priority: Priority) -> Result<(), Error> {
+        self.check_not_closing()?;
+        let m = Message::Notification { params };
+        self.sink.push(priority, SinkValue::Message(m));
+        Ok(())
+    }
+
+    // Allocates an id and returns a sender that streams a large payload's
+    // chunks out through this connection's priority sink, interleaved with
+    // other traffic rather than buffered whole. The id is meant to be
+    // carried alongside the associated request/response so the remote
+    // knows which body the frames belong to.
+    pub fn open_body(&self) -> Result<(u32, BodySender<P, NP, R>), Error> {
+        let id = {
+            let mut queue = self.queue.lock()?;
+            if queue.closing {
+                return Err(Error::TransportError(
+                    "connection is shutting down".into(),
+                ));
+            }
+            queue.next_id()
+        };
+        Ok((id, BodySender::new(id, self.sink.clone())))
+    }
+
+    // Registers a body under `id` and returns a `Read` handle that blocks
+    // for chunks as they arrive, bounded so a slow reader here exerts
+    // backpressure on the remote producer.
+    pub fn receive_body(&self, id: u32) -> Result<Body, Error> {
+        let mut queue = self.queue.lock()?;
+        Ok(queue.bodies.register(id))
     }
 
     #[allow(clippy::needless_lifetimes)]
@@ -110,7 +404,7 @@ where
     where
         D: Fn(R) -> Option<RR>,
     {
-        match self.call_raw(params) {
+        match self.call_raw_timeout(params, default_timeout()) {
             Ok(m) => match m {
                 Message::Response { results, error, .. } => {
                     if let Some(error) = error {
@@ -159,6 +453,13 @@ where
     proto_client: Client<P, NP, R>,
     err_rx: mpsc::Receiver<Result<(), Box<dyn Any + Send>>>,
     shutdown_handle: C,
+    // Signalled by the encoder thread right before it returns on
+    // `SinkValue::Shutdown`, i.e. once every write queued ahead of the
+    // shutdown marker (including the one the drain loop was waiting to see
+    // flushed) has had its `write_all`/`flush` finish. Lets
+    // `shutdown_gracefully` avoid racing that write with a raw
+    // `shutdown(Shutdown::Both)` on the same socket.
+    encoder_done_rx: mpsc::Receiver<()>,
 }
 
 impl<C, P, NP, R> Runtime<C, P, NP, R>
@@ -196,6 +497,60 @@ where
         self.shutdown_handle.shutdown(Shutdown::Both)?;
         Ok(())
     }
+
+    // Stops accepting new outbound requests, then waits for the already
+    // in-flight ones to be answered (or for `timeout` to elapse) before
+    // closing the connection, so callers don't see a spurious
+    // `TransportError` for a response that was already on its way.
+    pub fn shutdown_gracefully(&self, timeout: Duration) -> Result<(), Error> {
+        debug!("Runtime: shutting down gracefully");
+        {
+            let mut queue = self.proto_client.queue.lock()?;
+            queue.closing = true;
+        }
+
+        // Besides our own outbound calls (`in_flight_requests`), also wait
+        // for the sink to drain and for any inbound requests this side is
+        // still handling (`inbound_in_flight`): both can still push a
+        // message after the check above and before we queue `Shutdown`,
+        // and that message needs to be flushed, not dropped.
+        let deadline = Instant::now() + timeout;
+        loop {
+            let drained = {
+                let queue = self.proto_client.queue.lock()?;
+                queue.in_flight_requests.is_empty() && queue.inbound_in_flight == 0
+            } && self.proto_client.sink.is_empty();
+            if drained || Instant::now() >= deadline {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+
+        {
+            let mut queue = self.proto_client.queue.lock()?;
+            for (_, in_flight) in queue.in_flight_requests.drain() {
+                let _ = in_flight.tx.send(Err(Error::TransportError(
+                    "connection shut down before a response arrived".into(),
+                )));
+            }
+        }
+
+        self.proto_client
+            .sink
+            .push(PRIORITY_SHUTDOWN, SinkValue::Shutdown);
+
+        // Wait for the encoder thread to actually exit rather than racing
+        // its in-flight write_all/flush of whatever it dequeued last
+        // (possibly the very message the drain loop above was waiting on)
+        // with a raw shutdown() on the same socket. The wait is bounded by
+        // what is left of `timeout`; once the deadline has passed, the
+        // socket is shut down without waiting.
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let _ = self.encoder_done_rx.recv_timeout(remaining);
+
+        self.shutdown_handle.shutdown(Shutdown::Both)?;
+        Ok(())
+    }
 }
 
 pub fn spawn<C, AH, H, P, NP, R>(handler: AH, conn: C) -> Result<Runtime<C, P, NP, R>, Error>
@@ -210,29 +565,39 @@ where
     let handler = handler.into();
     let queue = Arc::new(Mutex::new(Queue::new()));
 
+    let peer_identity = conn.peer_identity();
     let shutdown_handle = conn.try_clone()?;
     let write = conn.try_clone()?;
     let read = conn;
     let mut decoder = Decoder::new(read, queue.clone());
     let mut encoder = Encoder::new(write);
-    let (tx, rx) = mpsc::channel();
+    let sink = Arc::new(Sink::<P, NP, R>::new());
 
     let client = Client::<P, NP, R> {
         queue: queue.clone(),
-        sink: tx,
+        sink: sink.clone(),
+        peer_identity,
     };
 
     let proto_client = client.clone();
     let (err_tx, err_rx) = mpsc::channel();
     let err_tx2 = err_tx.clone();
+    let (encoder_done_tx, encoder_done_rx) = mpsc::channel();
 
     let encode_handle = std::thread::spawn(move || loop {
-        match rx.recv().unwrap() {
-            SinkValue::Message(m) => {
-                encoder.encode(m).unwrap();
+        match sink.pop() {
+            (priority, SinkValue::Message(m)) => {
+                encoder.encode(priority, m).unwrap();
+            }
+            (priority, SinkValue::Stream(frame)) => {
+                encoder.encode_stream_frame(priority, frame).unwrap();
+            }
+            (_, SinkValue::Cancel(id)) => {
+                encoder.encode_cancel(id).unwrap();
             }
-            SinkValue::Shutdown => {
+            (_, SinkValue::Shutdown) => {
                 debug!("Encoder loop: dropping receiver");
+                let _ = encoder_done_tx.send(());
                 return;
             }
         }
@@ -242,12 +607,12 @@ where
     });
 
     let decode_handle = std::thread::spawn(move || loop {
-        let m = decoder.decode().unwrap();
+        let (priority, m) = decoder.decode().unwrap();
         let handler = handler.clone();
         let client = client.clone();
 
         std::thread::spawn(move || {
-            let res = handle_message(m, handler, client);
+            let res = handle_message(m, priority, handler, client);
             if let Err(e) = res {
                 eprintln!("message stream error: {:#?}", e);
             }
@@ -261,11 +626,13 @@ where
         proto_client,
         err_rx,
         shutdown_handle,
+        encoder_done_rx,
     })
 }
 
 fn handle_message<P, NP, R, H>(
     inbound: Message<P, NP, R>,
+    priority: Priority,
     handler: Arc<H>,
     client: Client<P, NP, R>,
 ) -> Result<(), Error>
@@ -277,6 +644,14 @@ where
 {
     match inbound {
         Message::Request { id, params } => {
+            // Counted so `shutdown_gracefully` can wait for requests we're
+            // still handling on behalf of the peer, not just our own
+            // outbound calls, before it lets the connection close.
+            client.queue.lock()?.inbound_in_flight += 1;
+            let _inbound_guard = InboundGuard {
+                queue: client.queue.clone(),
+            };
+
             let m = match handler.handle(client.clone(), params) {
                 Ok(results) => Message::Response::<P, NP, R> {
                     id,
@@ -289,7 +664,17 @@ where
                     error: Some(format!("internal error: {:#?}", error)),
                 },
             };
-            client.sink.send(SinkValue::Message(m)).unwrap();
+            // The caller may have given up and cancelled `id` while we were
+            // computing the response; don't bother sending it back.
+            if client.queue.lock()?.take_cancelled(id) {
+                debug!("Dropping response for cancelled request {}", id);
+            } else {
+                // Reply at the priority the request arrived at, rather than
+                // always falling back to PRIORITY_NORMAL, so a high-priority
+                // caller's response isn't stuck behind unrelated bulk
+                // traffic queued in the meantime.
+                client.sink.push(priority, SinkValue::Message(m));
+            }
         }
         Message::Response { id, error, results } => {
             if let Some(in_flight) = {
@@ -298,11 +683,13 @@ where
             } {
                 in_flight
                     .tx
-                    .send(Message::Response { id, error, results })
+                    .send(Ok(Message::Response { id, error, results }))
                     .unwrap();
             }
         }
-        Message::Notification { .. } => unimplemented!(),
+        Message::Notification { params } => {
+            handler.on_notification(client.clone(), params);
+        }
     };
     Ok(())
 }
@@ -314,7 +701,33 @@ where
     R: Atom,
 {
     method: &'static str,
-    tx: mpsc::Sender<Message<P, NP, R>>,
+    tx: mpsc::Sender<Result<Message<P, NP, R>, Error>>,
+}
+
+// Decrements `inbound_in_flight` when a request handler returns, including
+// via an early return or a panic unwinding through it, so a handler that
+// fails can't leave the count permanently elevated. A handler that never
+// returns keeps it elevated.
+struct InboundGuard<P, NP, R>
+where
+    P: Atom,
+    NP: Atom,
+    R: Atom,
+{
+    queue: Arc<Mutex<Queue<P, NP, R>>>,
+}
+
+impl<P, NP, R> Drop for InboundGuard<P, NP, R>
+where
+    P: Atom,
+    NP: Atom,
+    R: Atom,
+{
+    fn drop(&mut self) {
+        if let Ok(mut queue) = self.queue.lock() {
+            queue.inbound_in_flight -= 1;
+        }
+    }
 }
 
 pub struct Queue<P, NP, R>
@@ -325,6 +738,10 @@ where
 {
     id: u32,
     in_flight_requests: HashMap<u32, InFlightRequest<P, NP, R>>,
+    bodies: BodyRegistry,
+    closing: bool,
+    cancelled: HashSet<u32>,
+    inbound_in_flight: usize,
 }
 
 impl<P, NP, R> Queue<P, NP, R>
@@ -337,6 +754,10 @@ where
         Queue {
             id: 0,
             in_flight_requests: HashMap::new(),
+            bodies: BodyRegistry::new(),
+            closing: false,
+            cancelled: HashSet::new(),
+            inbound_in_flight: 0,
         }
     }
 
@@ -345,6 +766,32 @@ where
         self.id += 1;
         res
     }
+
+    // Called by the decoder as stream frames arrive, to look up whichever
+    // body was registered for that frame's id via `Client::receive_body`.
+    // Returns a clone of the sender (not the frame handling itself) so the
+    // decoder can feed it outside the `Queue` lock — `SyncSender::send`
+    // blocks when the body's bounded channel is full, and blocking here
+    // while holding the lock would freeze every other use of this
+    // connection's `Queue` behind one slow reader.
+    pub(crate) fn take_body_sender(&mut self, id: u32) -> Option<mpsc::SyncSender<BodyFrame>> {
+        self.bodies.take_sender(id)
+    }
+
+    pub(crate) fn forget_body(&mut self, id: u32) {
+        self.bodies.forget(id);
+    }
+
+    // Called by the decoder when a `Cancel` frame arrives for a request
+    // we're still handling, so its response can be dropped once ready
+    // instead of sent to a caller that has already given up.
+    pub(crate) fn mark_cancelled(&mut self, id: u32) {
+        self.cancelled.insert(id);
+    }
+
+    fn take_cancelled(&mut self, id: u32) -> bool {
+        self.cancelled.remove(&id)
+    }
 }
 
 impl<P, NP, R> PendingRequests for Queue<P, NP, R>
@@ -357,3 +804,170 @@ where
         self.in_flight_requests.get(&id).map(|req| req.method)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Stands in for a real `Atom` implementation in these tests: nothing
+    // here exercises serialization, only the `Sink`/`Queue` plumbing around
+    // it, so a bare `method()` is all that's needed.
+    #[derive(Clone, Debug)]
+    struct TestParams;
+
+    impl Atom for TestParams {
+        fn method(&self) -> &'static str {
+            "test.method"
+        }
+    }
+
+    fn test_client() -> (Client<TestParams, TestParams, TestParams>, Arc<Sink<TestParams, TestParams, TestParams>>) {
+        let sink = Arc::new(Sink::<TestParams, TestParams, TestParams>::new());
+        let queue = Arc::new(Mutex::new(Queue::<TestParams, TestParams, TestParams>::new()));
+        let client = Client {
+            queue,
+            sink: sink.clone(),
+            peer_identity: None,
+        };
+        (client, sink)
+    }
+
+    #[test]
+    fn sink_pops_higher_priority_before_lower() {
+        let sink = Sink::<TestParams, TestParams, TestParams>::new();
+        sink.push(PRIORITY_NORMAL, SinkValue::Cancel(1));
+        sink.push(PRIORITY_HIGH, SinkValue::Cancel(2));
+        sink.push(PRIORITY_SHUTDOWN, SinkValue::Cancel(3));
+
+        let (priority, value) = sink.pop();
+        assert_eq!(priority, PRIORITY_HIGH);
+        assert!(matches!(value, SinkValue::Cancel(2)));
+
+        let (priority, value) = sink.pop();
+        assert_eq!(priority, PRIORITY_NORMAL);
+        assert!(matches!(value, SinkValue::Cancel(1)));
+
+        let (priority, value) = sink.pop();
+        assert_eq!(priority, PRIORITY_SHUTDOWN);
+        assert!(matches!(value, SinkValue::Cancel(3)));
+    }
+
+    #[test]
+    fn sink_keeps_fifo_order_within_the_same_priority() {
+        let sink = Sink::<TestParams, TestParams, TestParams>::new();
+        for id in 0..4 {
+            sink.push(PRIORITY_NORMAL, SinkValue::Cancel(id));
+        }
+
+        for expected in 0..4 {
+            let (_, value) = sink.pop();
+            assert!(matches!(value, SinkValue::Cancel(id) if id == expected));
+        }
+    }
+
+    #[test]
+    fn sink_pops_shutdown_after_traffic_queued_ahead_of_it_at_the_lowest_priority() {
+        // `PRIORITY_SHUTDOWN` exists precisely so `Shutdown` never overtakes
+        // work that was already queued ahead of it, even work queued at the
+        // lowest priority a caller can pick.
+        let sink = Sink::<TestParams, TestParams, TestParams>::new();
+        sink.push(0, SinkValue::Cancel(1));
+        sink.push(PRIORITY_SHUTDOWN, SinkValue::Shutdown);
+
+        let (_, value) = sink.pop();
+        assert!(matches!(value, SinkValue::Cancel(1)));
+        let (_, value) = sink.pop();
+        assert!(matches!(value, SinkValue::Shutdown));
+    }
+
+    #[test]
+    fn mark_cancelled_then_take_cancelled_is_one_shot() {
+        let mut queue = Queue::<TestParams, TestParams, TestParams>::new();
+        assert!(!queue.take_cancelled(7));
+
+        queue.mark_cancelled(7);
+        assert!(queue.take_cancelled(7));
+        // Taking it again finds nothing: `handle_message` only ever needs to
+        // know once that a response is pointless to send.
+        assert!(!queue.take_cancelled(7));
+    }
+
+    #[test]
+    fn notify_is_rejected_once_the_queue_is_closing() {
+        let (client, _sink) = test_client();
+        client.queue.lock().unwrap().closing = true;
+
+        let err = client
+            .notify_with_priority(TestParams, PRIORITY_NORMAL)
+            .unwrap_err();
+        assert!(matches!(err, Error::TransportError(_)));
+    }
+
+    #[test]
+    fn open_body_is_rejected_once_the_queue_is_closing() {
+        let (client, _sink) = test_client();
+        client.queue.lock().unwrap().closing = true;
+
+        let err = client.open_body().unwrap_err();
+        assert!(matches!(err, Error::TransportError(_)));
+    }
+
+    #[test]
+    fn call_raw_timeout_cancels_the_request_and_reports_timeout() {
+        let (client, sink) = test_client();
+
+        // Nothing is ever popping `sink`, so this is guaranteed to hit the
+        // timeout path rather than ever get a response.
+        let err = client
+            .call_raw_timeout_with_priority(TestParams, Duration::from_millis(10), PRIORITY_NORMAL)
+            .unwrap_err();
+        assert!(matches!(err, Error::Timeout));
+
+        // The cancel is pushed at PRIORITY_HIGH, so it pops ahead of the
+        // PRIORITY_NORMAL request message still sitting behind it.
+        let (priority, value) = sink.pop();
+        assert_eq!(priority, PRIORITY_HIGH);
+        let cancelled_id = match value {
+            SinkValue::Cancel(id) => id,
+            _ => panic!("expected a Cancel entry"),
+        };
+
+        let (_, value) = sink.pop();
+        assert!(matches!(value, SinkValue::Message(_)));
+
+        assert!(!client
+            .queue
+            .lock()
+            .unwrap()
+            .in_flight_requests
+            .contains_key(&cancelled_id));
+    }
+
+    #[test]
+    fn shutdown_drain_condition_waits_for_in_flight_work_and_an_empty_sink() {
+        // Mirrors the predicate `shutdown_gracefully` polls: it isn't safe
+        // to push `Shutdown` until our own in-flight requests, any inbound
+        // requests we're still handling, and the sink itself have all
+        // drained.
+        let (client, sink) = test_client();
+        let drained = |client: &Client<TestParams, TestParams, TestParams>| {
+            let queue = client.queue.lock().unwrap();
+            queue.in_flight_requests.is_empty() && queue.inbound_in_flight == 0 && sink.is_empty()
+        };
+
+        assert!(drained(&client));
+
+        let (id, _rx) = client.enqueue_request(TestParams, PRIORITY_NORMAL).unwrap();
+        assert!(!drained(&client), "an in-flight request must block the drain");
+        client.queue.lock().unwrap().in_flight_requests.remove(&id);
+        // The request's `Message` is still sitting in the sink.
+        assert!(!drained(&client), "an undrained sink must block the drain");
+        let _ = sink.pop();
+        assert!(drained(&client));
+
+        client.queue.lock().unwrap().inbound_in_flight += 1;
+        assert!(!drained(&client), "in-flight inbound work must block the drain");
+        client.queue.lock().unwrap().inbound_in_flight -= 1;
+        assert!(drained(&client));
+    }
+}